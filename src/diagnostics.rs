@@ -0,0 +1,270 @@
+use crate::expr::{expression, Node, Op, OpModifier};
+
+// what `diagnose()` thinks was missing at a given byte offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expected {
+	Operand,
+	Operator,
+	ClosingParen,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+	pub offset: usize,
+	pub expected: Expected,
+}
+
+// operator tokens `expression()` understands, longest first so e.g. "==" isn't mistaken for a
+// dangling "=" (which isn't a token at all, but better safe than misreporting the column)
+const OPERATOR_TOKENS: &[&str] = &[
+	"==", "!=", "<=", ">=", "<", ">", "^", "*", "/", "%", "+", "-", "and", "unless", "or",
+];
+
+fn starts_with_operator(input: &str) -> bool {
+	OPERATOR_TOKENS.iter().any(|tok| input.starts_with(tok))
+}
+
+// the `Op` a recognized operator token desugars to, kept in sync with `OPERATOR_TOKENS` by hand
+// since there's no single source of truth for the token <-> variant mapping in `expr.rs`
+fn operator_for_token(tok: &str) -> Op {
+	match tok {
+		"==" => Op::Eq,
+		"!=" => Op::Ne,
+		"<=" => Op::Le,
+		">=" => Op::Ge,
+		"<" => Op::Lt,
+		">" => Op::Gt,
+		"^" => Op::Pow,
+		"*" => Op::Mul,
+		"/" => Op::Div,
+		"%" => Op::Mod,
+		"+" => Op::Plus,
+		"-" => Op::Minus,
+		"and" => Op::And,
+		"unless" => Op::Unless,
+		"or" => Op::Or,
+		_ => unreachable!("OPERATOR_TOKENS and operator_for_token must stay in sync"),
+	}
+}
+
+// joins two nodes with a plain operator, no matching/bool modifier attached — `Node::operator`
+// isn't visible outside `expr.rs`, so this is the diagnostics-module equivalent
+fn join(x: Node, op: Op, y: Node) -> Node {
+	Node::Operator(Box::new(x), op, OpModifier::default(), Box::new(y))
+}
+
+// classifies the unconsumed tail left over after a successful-but-partial parse: either it's a
+// dangling operator with nothing after it ("1 +"), or an extra, unexpected token glued on with
+// no connecting operator — another operand ("1 2") or a stray closing paren ("1)") are the same
+// mistake from the parser's point of view, so both report `Operator`
+fn classify_trailing(rest: &str) -> Expected {
+	let rest = rest.trim_start();
+	if starts_with_operator(rest) {
+		Expected::Operand
+	} else {
+		Expected::Operator
+	}
+}
+
+// `diagnose()`'s helper for recursing into whatever text follows a recovered gap: unlike
+// `diagnose()` itself, this never gives up, so every recursive splice always has a real `Node`
+// to attach to a `join()` — empty input (nothing left to recover) and a total parse failure both
+// fall back to the `Node::Error` placeholder instead of bubbling up `None`
+fn diagnose_or_placeholder(input: &str) -> (Node, Vec<Diagnostic>) {
+	if input.trim_start().is_empty() {
+		return (Node::Error, Vec::new());
+	}
+	match diagnose(input) {
+		(Some(node), diagnostics) => (node, diagnostics),
+		(None, diagnostics) => (Node::Error, diagnostics),
+	}
+}
+
+/// `expression()`'s lenient sibling: instead of bailing out at the first syntax error, this
+/// recovers by splicing a `Node::Error` placeholder in for whatever didn't parse and continuing
+/// from there, so a single call can report several mistakes *and* still return one `Node`
+/// covering every recognizable fragment of `input` — not just the first one. Each gap it
+/// recovers from also produces a `Diagnostic`: what looked wrong, and at which byte offset.
+/// Returns `None` only if nothing in `input` parsed at all.
+pub fn diagnose(input: &str) -> (Option<Node>, Vec<Diagnostic>) {
+	let mut diagnostics = Vec::new();
+
+	match expression(input) {
+		Ok((rest, node)) if rest.trim_start().is_empty() => (Some(node), diagnostics),
+
+		Ok((rest, node)) => {
+			let trimmed = rest.trim_start();
+			let expected = classify_trailing(trimmed);
+			diagnostics.push(Diagnostic {
+				offset: input.len() - rest.len(),
+				expected,
+			});
+
+			// a dangling operator ("1 +") has nothing after it but is itself a real, known
+			// operator, so keep it and splice the placeholder in as its missing right-hand
+			// side; anything else glued on with no connecting operator ("1 2", "1)") has no
+			// real operator to recover, so the join itself is a placeholder too
+			let (resume_at, op) = if expected == Expected::Operand {
+				let tok = OPERATOR_TOKENS
+					.iter()
+					.find(|tok| trimmed.starts_with(*tok))
+					.expect("classify_trailing only reports Operand when an operator matched");
+				(trimmed[tok.len()..].trim_start(), operator_for_token(tok))
+			} else {
+				(trimmed, Op::Error)
+			};
+
+			let (other, mut more) = diagnose_or_placeholder(resume_at);
+			let skipped = input.len() - resume_at.len();
+			for d in &mut more {
+				d.offset += skipped;
+			}
+			diagnostics.extend(more);
+			(Some(join(node, op, other)), diagnostics)
+		}
+
+		Err(_) => {
+			// the one structural failure we can pinpoint precisely: a parenthesized
+			// subexpression that never got its closing ')' just drags the whole parse down,
+			// even though everything up to end-of-input was otherwise fine
+			if let Some(inner) = input.strip_prefix('(') {
+				if let Ok((rest, node)) = expression(inner) {
+					if rest.trim_start().is_empty() {
+						diagnostics.push(Diagnostic {
+							offset: input.len(),
+							expected: Expected::ClosingParen,
+						});
+						return (Some(node), diagnostics);
+					}
+				}
+			}
+
+			// otherwise, fall back to skipping one byte at a time until something parses;
+			// bounded by the input length, so this always terminates
+			for skip in 1..=input.len() {
+				if !input.is_char_boundary(skip) {
+					continue;
+				}
+				if expression(&input[skip..]).is_ok() {
+					diagnostics.push(Diagnostic {
+						offset: 0,
+						expected: Expected::Operand,
+					});
+					// the skipped junk prefix becomes its own placeholder operand, joined to
+					// whatever the rest of the input recovers into; anything left over after
+					// the fragment that parsed is diagnosed the same way as above
+					let (node, mut more) = diagnose_or_placeholder(&input[skip..]);
+					for d in &mut more {
+						d.offset += skip;
+					}
+					diagnostics.extend(more);
+					return (Some(join(Node::Error, Op::Error, node)), diagnostics);
+				}
+			}
+
+			(None, diagnostics)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn clean_expression_has_no_diagnostics() {
+		let (node, diagnostics) = diagnose("foo + bar");
+		assert!(node.is_some());
+		assert_eq!(diagnostics, vec![]);
+	}
+
+	#[test]
+	fn missing_operand_after_operator() {
+		let (node, diagnostics) = diagnose("1 +");
+		assert_eq!(
+			node,
+			Some(join(Node::Scalar(1.), Op::Plus, Node::Error))
+		);
+		assert_eq!(
+			diagnostics,
+			vec![Diagnostic {
+				offset: 1,
+				expected: Expected::Operand,
+			}]
+		);
+	}
+
+	#[test]
+	fn missing_operator_between_operands() {
+		let (node, diagnostics) = diagnose("1 2");
+		assert_eq!(
+			node,
+			Some(join(Node::Scalar(1.), Op::Error, Node::Scalar(2.)))
+		);
+		assert_eq!(
+			diagnostics,
+			vec![Diagnostic {
+				offset: 1,
+				expected: Expected::Operator,
+			}]
+		);
+	}
+
+	#[test]
+	fn stray_closing_paren_is_an_unexpected_token_not_a_missing_operand() {
+		let (node, diagnostics) = diagnose("1)");
+		assert_eq!(
+			node,
+			Some(join(Node::Scalar(1.), Op::Error, Node::Error))
+		);
+		assert_eq!(
+			diagnostics,
+			vec![Diagnostic {
+				offset: 1,
+				expected: Expected::Operator,
+			}]
+		);
+	}
+
+	#[test]
+	fn missing_closing_paren() {
+		let (node, diagnostics) = diagnose("(1 + 2");
+		assert_eq!(
+			node,
+			Some(expression("1 + 2").unwrap().1)
+		);
+		assert_eq!(
+			diagnostics,
+			vec![Diagnostic {
+				offset: 6,
+				expected: Expected::ClosingParen,
+			}]
+		);
+	}
+
+	#[test]
+	fn reports_several_mistakes_at_once() {
+		let (node, diagnostics) = diagnose("1 + 2 3 +");
+		assert_eq!(
+			node,
+			Some(join(
+				join(Node::Scalar(1.), Op::Plus, Node::Scalar(2.)),
+				Op::Error,
+				join(Node::Scalar(3.), Op::Plus, Node::Error),
+			))
+		);
+		assert_eq!(
+			diagnostics,
+			vec![
+				Diagnostic {
+					offset: 5,
+					expected: Expected::Operator,
+				},
+				Diagnostic {
+					offset: 7,
+					expected: Expected::Operand,
+				},
+			]
+		);
+	}
+}
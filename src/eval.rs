@@ -0,0 +1,150 @@
+use crate::expr::{Node, Op, OpModifier};
+
+// constant folding only ever has to reject a handful of PromQL rules (bool-less scalar
+// comparisons, set operators or vector matching between scalars) plus placeholder operators
+// left by error recovery; everything else is plain arithmetic, so a
+// small quick_error enum is enough here, same as UnicodeRuneError in the str module.
+quick_error! {
+	#[derive(Debug, PartialEq)]
+	pub enum EvalError {
+		ScalarComparisonNeedsBool {
+			display("comparing two scalars requires the 'bool' modifier")
+		}
+		IllegalScalarSetOp(op: &'static str) {
+			display("'{}' is only defined between instant vectors, not scalars", op)
+		}
+		ScalarsCannotMatch {
+			display("vector matching ('on'/'ignoring') is not defined between scalars")
+		}
+		PlaceholderNode {
+			display("cannot evaluate a placeholder node produced by error recovery")
+		}
+	}
+}
+
+fn fold_scalar_op(a: f32, op: &Op, modifier: &OpModifier, b: f32) -> Result<f32, EvalError> {
+	Ok(match *op {
+		Op::Pow => a.powf(b),
+		Op::Mul => a * b,
+		// IEEE 754 division already yields NaN/inf for zero denominators, same as Prometheus
+		Op::Div => a / b,
+		Op::Mod => a % b,
+		Op::Plus => a + b,
+		Op::Minus => a - b,
+
+		Op::Eq | Op::Ne | Op::Lt | Op::Gt | Op::Le | Op::Ge => {
+			if !modifier.bool_modifier {
+				return Err(EvalError::ScalarComparisonNeedsBool);
+			}
+			let result = match *op {
+				Op::Eq => a == b,
+				Op::Ne => a != b,
+				Op::Lt => a < b,
+				Op::Gt => a > b,
+				Op::Le => a <= b,
+				Op::Ge => a >= b,
+				_ => unreachable!(),
+			};
+			if result {
+				1.
+			} else {
+				0.
+			}
+		}
+
+		Op::And => return Err(EvalError::IllegalScalarSetOp("and")),
+		Op::Unless => return Err(EvalError::IllegalScalarSetOp("unless")),
+		Op::Or => return Err(EvalError::IllegalScalarSetOp("or")),
+
+		Op::Error => return Err(EvalError::PlaceholderNode),
+	})
+}
+
+/// Walks a `Node` tree bottom-up and collapses any subtree made up entirely of `Scalar`
+/// operands into a single `Scalar`, applying PromQL's arithmetic and comparison semantics.
+/// Any subtree that still references an `InstantVector` is left untouched.
+pub fn fold_constants(node: Node) -> Result<Node, EvalError> {
+	match node {
+		Node::Operator(x, op, modifier, y) => {
+			let x = fold_constants(*x)?;
+			let y = fold_constants(*y)?;
+			match (x, y) {
+				(Node::Scalar(a), Node::Scalar(b)) => {
+					if modifier.matching.is_some() {
+						return Err(EvalError::ScalarsCannotMatch);
+					}
+					Ok(Node::Scalar(fold_scalar_op(a, &op, &modifier, b)?))
+				}
+				(x, y) => Ok(Node::Operator(Box::new(x), op, modifier, Box::new(y))),
+			}
+		}
+		other => Ok(other),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::expr::expression;
+
+	fn fold(expr: &str) -> Node {
+		let (rest, node) = expression(expr).expect("failed to parse expression");
+		assert_eq!(rest, "");
+		fold_constants(node).expect("failed to fold constants")
+	}
+
+	#[test]
+	fn arithmetic() {
+		assert_eq!(fold("(2+3)*4"), Node::Scalar(20.));
+		assert_eq!(fold("2^3^2"), Node::Scalar(512.)); // right-associative: 2^(3^2)
+		assert_eq!(fold("1/0"), Node::Scalar(f32::INFINITY));
+		assert!(matches!(fold("0/0"), Node::Scalar(n) if n.is_nan()));
+	}
+
+	#[test]
+	fn leaves_vectors_untouched() {
+		let (rest, node) = expression("1 + foo").expect("failed to parse expression");
+		assert_eq!(rest, "");
+		let folded = fold_constants(node).expect("failed to fold constants");
+		match folded {
+			Node::Operator(x, Op::Plus, _, y) => {
+				assert_eq!(*x, Node::Scalar(1.));
+				assert!(matches!(*y, Node::InstantVector(_)));
+			}
+			other => panic!("expected an unfolded operator node, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn rejects_bare_scalar_comparison() {
+		let (rest, node) = expression("1 == 1").expect("failed to parse expression");
+		assert_eq!(rest, "");
+		assert_eq!(
+			fold_constants(node),
+			Err(EvalError::ScalarComparisonNeedsBool)
+		);
+	}
+
+	#[test]
+	fn bool_modifier_allows_scalar_comparison() {
+		assert_eq!(fold("1 == bool 1"), Node::Scalar(1.));
+		assert_eq!(fold("1 == bool 2"), Node::Scalar(0.));
+	}
+
+	#[test]
+	fn rejects_scalar_set_ops() {
+		let (rest, node) = expression("1 and 1").expect("failed to parse expression");
+		assert_eq!(rest, "");
+		assert_eq!(
+			fold_constants(node),
+			Err(EvalError::IllegalScalarSetOp("and"))
+		);
+	}
+
+	#[test]
+	fn rejects_scalar_matching() {
+		let (rest, node) = expression("1 / on(x) 2").expect("failed to parse expression");
+		assert_eq!(rest, "");
+		assert_eq!(fold_constants(node), Err(EvalError::ScalarsCannotMatch));
+	}
+}
@@ -1,5 +1,13 @@
-use vec::{vector, Vector};
-use nom::{float, digit};
+use crate::utils::IResult;
+use crate::vec::{label_name, vector, Vector};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case};
+use nom::character::complete::{char, digit0, digit1, multispace0, satisfy};
+use nom::combinator::{complete, map, map_res, not, opt, peek, recognize};
+use nom::multi::{many0, separated_list0};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::{AsBytes, AsChar, Compare, InputIter, InputLength, InputTake, InputTakeAtPosition, Offset, Slice};
+use std::ops::{RangeFrom, RangeFull, RangeTo};
 
 #[derive(Debug, PartialEq)]
 pub enum Op {
@@ -23,129 +31,355 @@ pub enum Op {
 	Unless, // unless
 
 	Or, // or
+
+	// stands in for an operator that `diagnostics::diagnose` couldn't actually find while
+	// splicing recovered fragments back together; never produced by `expression()` itself
+	Error,
+}
+
+// `on(...)`/`ignoring(...)`
+#[derive(Debug, PartialEq)]
+pub enum MatchKeyword {
+	On,
+	Ignoring,
+}
+
+// `group_left(...)`/`group_right(...)`: which side is allowed to match more than one series,
+// plus the labels to additionally copy over from the 'one' side
+#[derive(Debug, PartialEq)]
+pub enum GroupSide {
+	Left,
+	Right,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VectorMatching {
+	pub keyword: MatchKeyword,
+	pub labels: Vec<String>,
+	pub group: Option<(GroupSide, Vec<String>)>,
+}
+
+// everything that can follow an operator token before the right-hand operand:
+// `foo / on(instance) group_left(job) bar`, `http_requests > bool 0`
+#[derive(Debug, PartialEq, Default)]
+pub struct OpModifier {
+	pub bool_modifier: bool,
+	pub matching: Option<VectorMatching>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Node {
-	Operator(Box<Node>, Op, Box<Node>),
+	Operator(Box<Node>, Op, OpModifier, Box<Node>),
 	InstantVector(Vector),
 	Scalar(f32),
+
+	// placeholder for a hole `diagnostics::diagnose` couldn't parse anything into; never
+	// produced by `expression()` itself
+	Error,
 }
 impl Node {
+	// plain operator with no matching/bool modifier, e.g. for desugared or hand-built nodes
 	fn operator(x: Node, op: Op, y: Node) -> Node {
-		Node::Operator(Box::new(x), op, Box::new(y))
+		Node::Operator(Box::new(x), op, OpModifier::default(), Box::new(y))
 	}
 }
 
-named!(atom <Node>, ws!(alt!(
-	map!(tag_no_case!("NaN"), |_| Node::Scalar(::std::f32::NAN)) // XXX define Node::NaN instead?
-	|
-	alt!(
-		// https://github.com/Geal/nom/issues/437
-		map!(float, Node::Scalar)
-		|
-		// from_utf8_unchecked() on [0-9]+ is actually totally safe
-		map_res!(digit, |x: &[u8]| unsafe { String::from_utf8_unchecked(x.to_vec()) }.parse::<f32>().map(Node::Scalar))
+// every combinator in this module is generic over the same input type, so they compose with
+// `vector`/`string` in the neighbouring modules without forcing callers to pick a concrete type;
+// this trait just bundles the (long, repetitive) bounds those combinators need.
+pub trait ExprInput:
+	Clone
+	+ Offset
+	+ AsBytes
+	+ Compare<&'static str>
+	+ InputIter<Item: AsChar + Clone>
+	+ InputLength
+	+ InputTake
+	+ InputTakeAtPosition<Item = <Self as InputIter>::Item>
+	+ Slice<RangeFrom<usize>>
+	+ Slice<RangeTo<usize>>
+	+ Slice<RangeFull>
+{
+}
+impl<T> ExprInput for T
+where
+	T: Clone
+		+ Offset
+		+ AsBytes
+		+ Compare<&'static str>
+		+ InputIter<Item: AsChar + Clone>
+		+ InputLength
+		+ InputTake
+		+ InputTakeAtPosition<Item = <T as InputIter>::Item>
+		+ Slice<RangeFrom<usize>>
+		+ Slice<RangeTo<usize>>
+		+ Slice<RangeFull>,
+{
+}
+
+// digits, optional fractional part, optional exponent - same shape as Go's/PromQL's number
+// literals. Hand-rolled (rather than nom::number::complete::float) so it stays generic over
+// the same input traits as the rest of this module instead of pulling in float()'s internal
+// 'Compare<&[u8]>'-style bounds, which only concrete input types can satisfy.
+fn number<I: ExprInput>(input: I) -> IResult<I, f32> {
+	map_res(
+		recognize(tuple((
+			alt((
+				recognize(pair(digit1, opt(preceded(char('.'), digit0)))),
+				// leading-dot form, e.g. ".5"
+				recognize(preceded(char('.'), digit1)),
+			)),
+			opt(preceded(
+				alt((char('e'), char('E'))),
+				pair(opt(alt((char('+'), char('-')))), digit1),
+			)),
+		))),
+		|digits: I| {
+			String::from_utf8(digits.as_bytes().to_vec())
+				.unwrap()
+				.parse::<f32>()
+		},
+	)(input)
+}
+
+// matches `tag_str` but only if it's not immediately followed by another identifier character,
+// so e.g. "bool" doesn't swallow the front of "boolean_metric" the way `label_name`'s character
+// class would reject it from being split there
+fn keyword<I: ExprInput>(tag_str: &'static str) -> impl FnMut(I) -> IResult<I, I> {
+	terminated(
+		tag(tag_str),
+		peek(not(satisfy(|c: char| c.is_alphanumeric() || c == '_'))),
 	)
-	|
-	map!(vector, Node::InstantVector)
-	|
-	delimited!(char!('('), expression, char!(')'))
-)));
-
-// ^ is right-associative, so we can actually keep it simple and recursive
-named!(power <Node>, ws!(do_parse!(
-	x: atom >>
-	y: opt!(complete!(preceded!(
-		tag!("^"),
-		power
-	))) >>
-	( match y {
-		None => x,
-		Some(y) => Node::operator(x, Op::Pow, y),
-	} )
-)));
+}
+
+// same as `keyword()`, but case-insensitive, for `NaN`/`Inf`-style literals
+fn keyword_no_case<I: ExprInput>(tag_str: &'static str) -> impl FnMut(I) -> IResult<I, I> {
+	terminated(
+		tag_no_case(tag_str),
+		peek(not(satisfy(|c: char| c.is_alphanumeric() || c == '_'))),
+	)
+}
+
+fn atom<I: ExprInput>(input: I) -> IResult<I, Node> {
+	preceded(
+		multispace0,
+		alt((
+			map(keyword_no_case("NaN"), |_| Node::Scalar(f32::NAN)),
+			map(keyword_no_case("Inf"), |_| Node::Scalar(f32::INFINITY)),
+			map(number, Node::Scalar),
+			map(vector, Node::InstantVector),
+			delimited(
+				preceded(multispace0, char('(')),
+				expression,
+				preceded(multispace0, char(')')),
+			),
+		)),
+	)(input)
+}
+
+// ^ is right-associative, so we can actually keep it simple and recursive; its right-hand
+// side goes through `unary` (not `power`) so things like `2^-2` parse
+fn power<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, x) = atom(input)?;
+	let (input, y) = opt(complete(preceded(preceded(multispace0, tag("^")), unary)))(input)?;
+	Ok((
+		input,
+		match y {
+			None => x,
+			Some(y) => Node::operator(x, Op::Pow, y),
+		},
+	))
+}
+
+// binds tighter than `*`/`/`/`%` but looser than `^`, matching Prometheus semantics
+// (`-2^2` is `-(2^2)`, while `-2*2` is `(-2)*2`)
+fn unary<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, sign) = opt(preceded(multispace0, alt((char('+'), char('-')))))(input)?;
+	let (input, x) = power(input)?;
+	Ok((
+		input,
+		match sign {
+			Some('-') => Node::operator(Node::Scalar(0.), Op::Minus, x),
+			_ => x,
+		},
+	))
+}
 
 // foo op bar op baz → Node[Node[foo op bar] op baz]
-macro_rules! left_op {
-	// $next is the parser for operator that takes precenence, or any other kind of non-operator token sequence
-	($name:ident, $next:ident!($($next_args:tt)*), $op:ident!($($op_args:tt)*)) => (
-		named!($name <Node>, ws!(do_parse!(
-			x: $next!($($next_args)*) >>
-			ops: many0!(tuple!(
-				$op!($($op_args)*),
-				$next!($($next_args)*)
-			)) >>
-			({
-				let mut x = x;
-				for (op, y) in ops {
-					x = Node::operator(x, op, y);
-				}
-				x
-			})
-		)));
-	);
-	($name:ident, $next:ident, $op:ident!($($op_args:tt)*)) => ( left_op!(
-		$name,
-		call!($next),
-		$op!($($op_args)*)
-	); );
-	($name:ident, $next:ident!($($next_args:tt)*), $op:ident) => ( left_op!(
-		$name,
-		$next!($($next_args)*),
-		call!($op)
-	); );
-	($name:ident, $next:ident, $op:ident) => ( left_op!(
-		$name,
-		call!($next),
-		call!($op)
-	); );
-}
-
-left_op!(mul_div_mod, power, alt!(
-	  tag!("*") => { |_| Op::Mul }
-	| tag!("/") => { |_| Op::Div }
-	| tag!("%") => { |_| Op::Mod }
-));
-
-left_op!(plus_minus, mul_div_mod, alt!(
-	  tag!("+") => { |_| Op::Plus }
-	| tag!("-") => { |_| Op::Minus }
-));
+fn fold_operators(x: Node, ops: Vec<(Op, OpModifier, Node)>) -> Node {
+	ops.into_iter().fold(x, |x, (op, modifier, y)| {
+		Node::Operator(Box::new(x), op, modifier, Box::new(y))
+	})
+}
+
+fn label_list<I: ExprInput>(input: I) -> IResult<I, Vec<String>> {
+	delimited(
+		preceded(multispace0, char('(')),
+		separated_list0(
+			preceded(multispace0, char(',')),
+			preceded(multispace0, label_name),
+		),
+		preceded(multispace0, char(')')),
+	)(input)
+}
+
+// `(on|ignoring) (labels) (group_left|group_right (labels)?)?`; grouping is only meaningful
+// for arithmetic/comparison operators, so set operators parse with `allow_group: false`
+fn matching_clause<I: ExprInput>(allow_group: bool) -> impl FnMut(I) -> IResult<I, VectorMatching> {
+	move |input: I| {
+		let (input, keyword_) = preceded(
+			multispace0,
+			alt((
+				map(keyword("on"), |_| MatchKeyword::On),
+				map(keyword("ignoring"), |_| MatchKeyword::Ignoring),
+			)),
+		)(input)?;
+		let (input, labels) = label_list(input)?;
+		let (input, group) = if allow_group {
+			opt(pair(
+				preceded(
+					multispace0,
+					alt((
+						map(keyword("group_left"), |_| GroupSide::Left),
+						map(keyword("group_right"), |_| GroupSide::Right),
+					)),
+				),
+				map(opt(label_list), Option::unwrap_or_default),
+			))(input)?
+		} else {
+			(input, None)
+		};
+		Ok((
+			input,
+			VectorMatching {
+				keyword: keyword_,
+				labels,
+				group,
+			},
+		))
+	}
+}
+
+// `bool`? `(on|ignoring ...)`?; `bool` only makes sense on comparison operators, so
+// `allow_bool` is false for arithmetic and set operators. "Rejecting" `bool` on set operators
+// or `group_left`/`group_right` without a matching clause just means this parser declines to
+// consume them, the same way every other combinator here works; like the rest of the grammar,
+// nothing downstream is `all_consuming`, so a caller that wants a hard parse error out of a
+// rejected modifier has to check that `expression()`'s leftover input is empty.
+fn op_modifier<I: ExprInput>(allow_bool: bool, allow_group: bool) -> impl FnMut(I) -> IResult<I, OpModifier> {
+	move |input: I| {
+		let (input, bool_modifier) = if allow_bool {
+			map(opt(preceded(multispace0, keyword("bool"))), |b| b.is_some())(input)?
+		} else {
+			(input, false)
+		};
+		let (input, matching) = opt(matching_clause(allow_group))(input)?;
+		Ok((
+			input,
+			OpModifier {
+				bool_modifier,
+				matching,
+			},
+		))
+	}
+}
+
+fn mul_div_mod<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, x) = unary(input)?;
+	let (input, ops) = many0(tuple((
+		preceded(
+			multispace0,
+			alt((
+				map(tag("*"), |_| Op::Mul),
+				map(tag("/"), |_| Op::Div),
+				map(tag("%"), |_| Op::Mod),
+			)),
+		),
+		op_modifier(false, true),
+		unary,
+	)))(input)?;
+	Ok((input, fold_operators(x, ops)))
+}
+
+fn plus_minus<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, x) = mul_div_mod(input)?;
+	let (input, ops) = many0(tuple((
+		preceded(
+			multispace0,
+			alt((
+				map(tag("+"), |_| Op::Plus),
+				map(tag("-"), |_| Op::Minus),
+			)),
+		),
+		op_modifier(false, true),
+		mul_div_mod,
+	)))(input)?;
+	Ok((input, fold_operators(x, ops)))
+}
 
 // if you thing this kind of operator chaining makes little to no sense, think again: it actually matches 'foo' that is both '> bar' and '!= baz'.
 // or, speaking another way: comparison operators are really just filters for values in a vector, and this is a chain of filters.
-left_op!(comparison, plus_minus, alt!(
-	  tag!("==") => { |_| Op::Eq }
-	| tag!("!=") => { |_| Op::Ne }
-	| tag!("<=") => { |_| Op::Le }
-	| tag!(">=") => { |_| Op::Ge }
-	| tag!("<")  => { |_| Op::Lt }
-	| tag!(">")  => { |_| Op::Gt }
-));
+fn comparison<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, x) = plus_minus(input)?;
+	let (input, ops) = many0(tuple((
+		preceded(
+			multispace0,
+			alt((
+				map(tag("=="), |_| Op::Eq),
+				map(tag("!="), |_| Op::Ne),
+				map(tag("<="), |_| Op::Le),
+				map(tag(">="), |_| Op::Ge),
+				map(tag("<"), |_| Op::Lt),
+				map(tag(">"), |_| Op::Gt),
+			)),
+		),
+		op_modifier(true, true),
+		plus_minus,
+	)))(input)?;
+	Ok((input, fold_operators(x, ops)))
+}
 
-left_op!(and_unless, comparison, alt!(
-	  tag!("and") => { |_| Op::And }
-	| tag!("unless") => { |_| Op::Unless }
-));
+fn and_unless<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, x) = comparison(input)?;
+	let (input, ops) = many0(tuple((
+		preceded(
+			multispace0,
+			alt((
+				map(tag("and"), |_| Op::And),
+				map(tag("unless"), |_| Op::Unless),
+			)),
+		),
+		op_modifier(false, false),
+		comparison,
+	)))(input)?;
+	Ok((input, fold_operators(x, ops)))
+}
 
-left_op!(or_op, and_unless, map!(tag!("or"), |_| Op::Or));
+fn or_op<I: ExprInput>(input: I) -> IResult<I, Node> {
+	let (input, x) = and_unless(input)?;
+	let (input, ops) = many0(tuple((
+		preceded(multispace0, map(tag("or"), |_| Op::Or)),
+		op_modifier(false, false),
+		and_unless,
+	)))(input)?;
+	Ok((input, fold_operators(x, ops)))
+}
 
-named!(pub expression <Node>, call!(or_op));
+pub fn expression<I: ExprInput>(input: I) -> IResult<I, Node> {
+	or_op(input)
+}
 
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use vec;
-	use nom::IResult::*;
-	use nom::ErrorKind;
+	use crate::vec;
 
 	// we can't make vec::Vector ourselves due to private fields,
 	// and we really don't need to 'cause that's what's already tested in the 'mod vec'
 	fn vector(expr: &str) -> Node {
-		match vec::vector(expr.as_bytes()) {
-			Done(b"", x) => Node::InstantVector(x),
-			_ => panic!("failed to parse label correctly")
+		match vec::vector(expr) {
+			Ok(("", x)) => Node::InstantVector(x),
+			_ => panic!("failed to parse label correctly"),
 		}
 	}
 
@@ -157,56 +391,228 @@ mod tests {
 		let operator = Node::operator;
 
 		assert_eq!(
-			expression(&b"foo > bar != 0 and 15.5 < xyzzy"[..]),
-			Done(&b""[..], operator(
+			expression("foo > bar != 0 and 15.5 < xyzzy"),
+			Ok((
+				"",
 				operator(
-					operator(vector("foo"), Gt, vector("bar")),
-					Ne,
-					Scalar(0.)
-				),
-				And,
-				operator(Scalar(15.5), Lt, vector("xyzzy")),
+					operator(
+						operator(vector("foo"), Gt, vector("bar")),
+						Ne,
+						Scalar(0.)
+					),
+					And,
+					operator(Scalar(15.5), Lt, vector("xyzzy")),
+				)
 			))
 		);
 
 		assert_eq!(
-			expression(&b"foo + bar - baz <= quux + xyzzy"[..]),
-			Done(&b""[..], operator(
+			expression("foo + bar - baz <= quux + xyzzy"),
+			Ok((
+				"",
 				operator(
-					operator(vector("foo"), Plus, vector("bar")),
-					Minus,
-					vector("baz"),
-				),
-				Le,
-				operator(vector("quux"), Plus, vector("xyzzy")),
+					operator(
+						operator(vector("foo"), Plus, vector("bar")),
+						Minus,
+						vector("baz"),
+					),
+					Le,
+					operator(vector("quux"), Plus, vector("xyzzy")),
+				)
 			))
 		);
 
 		assert_eq!(
-			expression(&b"foo + bar % baz"[..]),
-			Done(&b""[..], operator(
-				vector("foo"),
-				Plus,
-				operator(vector("bar"), Mod, vector("baz")),
+			expression("foo + bar % baz"),
+			Ok((
+				"",
+				operator(
+					vector("foo"),
+					Plus,
+					operator(vector("bar"), Mod, vector("baz")),
+				)
 			))
 		);
 
 		assert_eq!(
-			expression(&b"x^y^z"[..]),
-			Done(&b""[..], operator(
-				vector("x"),
-				Pow,
-				operator(vector("y"), Pow, vector("z")),
+			expression("x^y^z"),
+			Ok((
+				"",
+				operator(
+					vector("x"),
+					Pow,
+					operator(vector("y"), Pow, vector("z")),
+				)
 			))
 		);
 
 		assert_eq!(
-			expression(&b"(a+b)*c"[..]),
-			Done(&b""[..], operator(
-				operator(vector("a"), Plus, vector("b")),
-				Mul,
-				vector("c"),
+			expression("(a+b)*c"),
+			Ok((
+				"",
+				operator(
+					operator(vector("a"), Plus, vector("b")),
+					Mul,
+					vector("c"),
+				)
 			))
 		);
 	}
+
+	#[test]
+	fn leading_dot_number() {
+		assert_eq!(expression(".5"), Ok(("", Node::Scalar(0.5))));
+		assert_eq!(expression(".5e2"), Ok(("", Node::Scalar(50.))));
+	}
+
+	#[test]
+	fn unary_sign() {
+		use self::Node::Scalar;
+		use self::Op::*;
+		let operator = Node::operator;
+
+		assert_eq!(
+			expression("-foo"),
+			Ok(("", operator(Scalar(0.), Minus, vector("foo"))))
+		);
+
+		assert_eq!(
+			expression("- 5 * bar"),
+			Ok((
+				"",
+				operator(
+					operator(Scalar(0.), Minus, Scalar(5.)),
+					Mul,
+					vector("bar"),
+				)
+			))
+		);
+
+		// unary binds looser than ^, so this is -(2^2), not (-2)^2
+		assert_eq!(
+			expression("-2^2"),
+			Ok((
+				"",
+				operator(Scalar(0.), Minus, operator(Scalar(2.), Pow, Scalar(2.)))
+			))
+		);
+
+		assert_eq!(expression("+Inf"), Ok(("", Scalar(f32::INFINITY))));
+		assert_eq!(
+			expression("-Inf"),
+			Ok(("", operator(Scalar(0.), Minus, Scalar(f32::INFINITY))))
+		);
+	}
+
+	#[test]
+	fn nan_inf_tags_respect_word_boundaries() {
+		// `infra_requests`/`nancy_bytes` are vector names, not `Inf`/`NaN` + garbage
+		assert_eq!(
+			expression("infra_requests"),
+			Ok(("", vector("infra_requests")))
+		);
+		assert_eq!(expression("nancy_bytes"), Ok(("", vector("nancy_bytes"))));
+	}
+
+	#[test]
+	fn matching_modifiers() {
+		assert_eq!(
+			expression("foo / on(instance) group_left(job) bar"),
+			Ok((
+				"",
+				Node::Operator(
+					Box::new(vector("foo")),
+					Op::Div,
+					OpModifier {
+						bool_modifier: false,
+						matching: Some(VectorMatching {
+							keyword: MatchKeyword::On,
+							labels: vec!["instance".to_string()],
+							group: Some((GroupSide::Left, vec!["job".to_string()])),
+						}),
+					},
+					Box::new(vector("bar")),
+				)
+			))
+		);
+
+		assert_eq!(
+			expression("http_requests > bool 0"),
+			Ok((
+				"",
+				Node::Operator(
+					Box::new(vector("http_requests")),
+					Op::Gt,
+					OpModifier {
+						bool_modifier: true,
+						matching: None,
+					},
+					Box::new(Node::Scalar(0.)),
+				)
+			))
+		);
+
+		assert_eq!(
+			expression("foo unless ignoring(job) bar"),
+			Ok((
+				"",
+				Node::Operator(
+					Box::new(vector("foo")),
+					Op::Unless,
+					OpModifier {
+						bool_modifier: false,
+						matching: Some(VectorMatching {
+							keyword: MatchKeyword::Ignoring,
+							labels: vec!["job".to_string()],
+							group: None,
+						}),
+					},
+					Box::new(vector("bar")),
+				)
+			))
+		);
+	}
+
+	#[test]
+	fn keyword_tags_respect_word_boundaries() {
+		// `boolean_metric` is a perfectly normal vector name, not `bool` + garbage
+		assert_eq!(
+			expression("foo > boolean_metric"),
+			Ok((
+				"",
+				Node::Operator(
+					Box::new(vector("foo")),
+					Op::Gt,
+					OpModifier {
+						bool_modifier: false,
+						matching: None,
+					},
+					Box::new(vector("boolean_metric")),
+				)
+			))
+		);
+
+		// `group_lefty` is not `group_left`; the optional grouping clause should simply not
+		// match here, so `group_lefty` is parsed as the (plain, ordinary) right-hand vector
+		// operand, leaving only the trailing "(y) bar" unconsumed, rather than misreading
+		// "group_left" + mangled vector "y" with "(y) bar" dangling
+		let (rest, node) = expression("foo / on(x) group_lefty(y) bar").unwrap();
+		assert_eq!(rest, "(y) bar");
+		assert_eq!(
+			node,
+			Node::Operator(
+				Box::new(vector("foo")),
+				Op::Div,
+				OpModifier {
+					bool_modifier: false,
+					matching: Some(VectorMatching {
+						keyword: MatchKeyword::On,
+						labels: vec!["x".to_string()],
+						group: None,
+					}),
+				},
+				Box::new(vector("group_lefty")),
+			)
+		);
+	}
 }